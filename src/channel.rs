@@ -0,0 +1,61 @@
+use crate::event::Event;
+
+enum Part {
+    Collection,
+    Db,
+    Id,
+    Literal(String),
+    Namespace,
+}
+
+/// Redis channel name with `{collection}`, `{db}`, `{id}`, and `{namespace}`
+/// placeholders. The `{prefix}` placeholder is expanded while parsing.
+#[derive(Default)]
+pub struct ChannelTemplate(Vec<Part>);
+
+impl ChannelTemplate {
+    /// Returns `None` if there is an unknown or unclosed placeholder.
+    pub fn parse(template: &str, prefix: &str) -> Option<Self> {
+        let mut parts = vec![];
+        let mut rest = template;
+        while let Some((literal, placeholder)) = rest.split_once('{') {
+            let (name, next) = placeholder.split_once('}')?;
+
+            if !literal.is_empty() {
+                parts.push(Part::Literal(literal.to_string()));
+            }
+
+            match name {
+                "collection" => parts.push(Part::Collection),
+                "db" => parts.push(Part::Db),
+                "id" => parts.push(Part::Id),
+                "namespace" => parts.push(Part::Namespace),
+                "prefix" => parts.extend(Self::parse(prefix, "")?.0),
+                _ => return None,
+            }
+
+            rest = next;
+        }
+
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Some(Self(parts))
+    }
+
+    pub fn render(&self, event: &Event, namespace: &str) -> String {
+        let mut channel = String::new();
+        for part in &self.0 {
+            channel.push_str(match part {
+                Part::Collection => &event.collection,
+                Part::Db => &event.db,
+                Part::Id => &event.document_id,
+                Part::Literal(literal) => literal,
+                Part::Namespace => namespace,
+            });
+        }
+
+        channel
+    }
+}
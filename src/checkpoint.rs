@@ -0,0 +1,140 @@
+use crate::{
+    event::{Event, Expansion},
+    redis::Redis,
+};
+use bson::{deserialize_from_bson, deserialize_from_slice, serialize_to_raw_document_buf};
+use mongodb::change_stream::event::ResumeToken;
+use redis::RedisError;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Display, Formatter},
+    fs::{read, rename, write},
+    io::ErrorKind,
+    path::PathBuf,
+};
+
+/// Where the resume tokens are stored between restarts.
+pub enum CheckpointStorage {
+    File(PathBuf),
+    Redis(String),
+}
+
+/// Resume tokens of the last published events of both change streams.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct ResumeTokens {
+    pub primary: Option<ResumeToken>,
+    pub secondary: Option<ResumeToken>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Bson(bson::error::Error),
+    Io(std::io::Error),
+    Redis(RedisError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bson(error) => write!(f, "Checkpoint BSON error: {error}"),
+            Self::Io(error) => write!(f, "Checkpoint I/O error: {error}"),
+            Self::Redis(error) => write!(f, "Checkpoint Redis error: {error}"),
+        }
+    }
+}
+
+impl From<bson::error::Error> for Error {
+    fn from(error: bson::error::Error) -> Self {
+        Self::Bson(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<RedisError> for Error {
+    fn from(error: RedisError) -> Self {
+        Self::Redis(error)
+    }
+}
+
+pub struct Checkpoint {
+    changed: bool,
+    storage: CheckpointStorage,
+    tokens: ResumeTokens,
+}
+
+impl Checkpoint {
+    /// Loads the last saved resume tokens (if any) from the storage.
+    pub async fn load(storage: CheckpointStorage, redis: &mut Redis) -> Result<Self, Error> {
+        let bytes = match &storage {
+            CheckpointStorage::File(path) => match read(path) {
+                Ok(bytes) => Some(bytes),
+                Err(error) if error.kind() == ErrorKind::NotFound => None,
+                Err(error) => return Err(error.into()),
+            },
+            CheckpointStorage::Redis(key) => redis.get(key).await?,
+        };
+
+        let tokens = match bytes {
+            None => ResumeTokens::default(),
+            Some(bytes) => deserialize_from_slice(&bytes)?,
+        };
+
+        println!("Checkpoint loaded.");
+        Ok(Self {
+            changed: false,
+            storage,
+            tokens,
+        })
+    }
+
+    pub const fn tokens(&self) -> &ResumeTokens {
+        &self.tokens
+    }
+
+    /// Remembers the resume tokens of the last events of both change streams. It has to be called
+    /// before the events are published, as `Redis::publish` consumes them. Partially expanded
+    /// renames are skipped, as they share the resume token of the last expanded event.
+    pub fn track(&mut self, events: &[Event]) -> Result<(), Error> {
+        let mut events = events
+            .iter()
+            .filter(|event| event.expansion != Expansion::Partial);
+        if let Some(event) = events.clone().rfind(|event| event.primary) {
+            self.tokens.primary = Some(deserialize_from_bson(event.event_id.clone())?);
+            self.changed = true;
+        }
+
+        if let Some(event) = events.rfind(|event| !event.primary) {
+            self.tokens.secondary = Some(deserialize_from_bson(event.event_id.clone())?);
+            self.changed = true;
+        }
+
+        Ok(())
+    }
+
+    /// Saves the tracked resume tokens. It should be called only after the events were published.
+    pub async fn save(&mut self, redis: &mut Redis) -> Result<(), Error> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        let bytes = serialize_to_raw_document_buf(&self.tokens)?.into_bytes();
+        match &self.storage {
+            CheckpointStorage::File(path) => {
+                // Write to a temporary file first, so a crash won't leave a partial checkpoint.
+                let mut temporary = path.clone().into_os_string();
+                temporary.push(".tmp");
+                write(&temporary, bytes)?;
+                rename(&temporary, path)?;
+            }
+            CheckpointStorage::Redis(key) => redis.set(key, bytes).await?,
+        }
+
+        self.changed = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{collections::BTreeMap, fs::read_to_string, path::Path};
+
+/// Configuration file, either TOML (`.toml`) or JSON (anything else). All
+/// top-level values are named after the environment variables (in any case),
+/// e.g., `redis_batch_size = 10`. Per-collection settings are grouped in the
+/// `collections` table.
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    collections: BTreeMap<String, CollectionConfig>,
+    #[serde(flatten)]
+    variables: BTreeMap<String, Value>,
+}
+
+/// Per-collection settings. Each of them is merged into the corresponding
+/// (collection-prefixed) list, e.g., `namespaces` into `NAMESPACES`.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CollectionConfig {
+    excluded: bool,
+    filter: Option<Value>,
+    full_document: bool,
+    id_format: Option<String>,
+    included: bool,
+    namespaces: Vec<String>,
+    operation_types: Vec<String>,
+    projection: Option<Value>,
+}
+
+/// Loads the configuration file as if it was a set of environment variables.
+pub fn load(path: &Path) -> Result<BTreeMap<String, String>, String> {
+    let content = read_to_string(path).map_err(|error| error.to_string())?;
+    let file: ConfigFile = if path.extension().is_some_and(|x| x == "toml") {
+        toml::from_str(&content).map_err(|error| error.to_string())?
+    } else {
+        serde_json::from_str(&content).map_err(|error| error.to_string())?
+    };
+
+    let mut variables = BTreeMap::new();
+    for (name, value) in file.variables {
+        let name = name.to_uppercase();
+        match value {
+            // Flags are set by their presence only.
+            Value::Bool(false) | Value::Null => {}
+            Value::Array(values) => {
+                let values = values.into_iter().map(|value| match value {
+                    Value::String(value) => value,
+                    value => value.to_string(),
+                });
+                variables.insert(name, values.collect::<Vec<_>>().join(","));
+            }
+            Value::String(value) => {
+                variables.insert(name, value);
+            }
+            value => {
+                variables.insert(name, value.to_string());
+            }
+        }
+    }
+
+    let mut filters = Map::new();
+    let mut projections = Map::new();
+    for (collection, config) in file.collections {
+        let mut push = |name: &str, value: String| {
+            variables
+                .entry(name.to_string())
+                .and_modify(|values: &mut String| {
+                    values.push(',');
+                    values.push_str(&value);
+                })
+                .or_insert(value);
+        };
+
+        if config.excluded {
+            push("EXCLUDED_COLLECTIONS", collection.clone());
+        }
+
+        if config.full_document {
+            push("FULL_DOCUMENT_COLLECTIONS", collection.clone());
+        }
+
+        if let Some(id_format) = config.id_format {
+            push("ID_FORMATS", format!("{collection}.{id_format}"));
+        }
+
+        if config.included {
+            push("INCLUDED_COLLECTIONS", collection.clone());
+        }
+
+        for namespace in config.namespaces {
+            push("NAMESPACES", format!("{collection}.{namespace}"));
+        }
+
+        for operation_type in config.operation_types {
+            push("OPERATION_TYPES", format!("{collection}.{operation_type}"));
+        }
+
+        if let Some(filter) = config.filter {
+            filters.insert(collection.clone(), filter);
+        }
+
+        if let Some(projection) = config.projection {
+            projections.insert(collection, projection);
+        }
+    }
+
+    merge_object(&mut variables, "FILTERS", filters)?;
+    merge_object(&mut variables, "PROJECTIONS", projections)?;
+    Ok(variables)
+}
+
+/// Merges the per-collection objects with the top-level one (if any).
+fn merge_object(
+    variables: &mut BTreeMap<String, String>,
+    name: &str,
+    mut object: Map<String, Value>,
+) -> Result<(), String> {
+    if object.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(value) = variables.get(name) {
+        match serde_json::from_str(value) {
+            Ok(Value::Object(top_level)) => object.extend(top_level),
+            _ => return Err(format!("{} has to be an object.", name.to_lowercase())),
+        }
+    }
+
+    variables.insert(name.to_string(), Value::Object(object).to_string());
+    Ok(())
+}
@@ -1,42 +1,125 @@
-use crate::ejson::Ejson;
-use bson::{Bson, Timestamp};
+use crate::{config::Config, ejson::Ejson, metrics::PAYLOAD_DOWNGRADE_COUNTER};
+use bson::{doc, Bson, Document, Timestamp};
 use serde::Deserialize;
 
+/// Whether the event was expanded from a rename (see `mongo::Rename`).
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum Expansion {
+    #[default]
+    None,
+    /// The last event of a rename, i.e., the only one checkpointed.
+    Last,
+    /// All other events of a rename. They are not checkpointed, so the whole
+    /// rename is expanded again after a restart.
+    Partial,
+}
+
 #[derive(Deserialize)]
 pub struct Event {
-    #[serde(rename = "c")]
+    #[serde(default, rename = "c")]
     pub collection: String,
-    #[serde(rename = "d")]
+    #[serde(default, rename = "d")]
     pub db: String,
-    #[serde(rename = "i")]
+    /// Stringified `_id` (see `id::stringify`).
+    #[serde(skip)]
     pub document_id: String,
+    #[serde(rename = "i")]
+    pub document_key: Option<Bson>,
     #[expect(clippy::struct_field_names)]
     #[serde(rename = "_id")]
     pub event_id: Bson,
+    #[serde(skip)]
+    pub expansion: Expansion,
     #[serde(rename = "n")]
     pub namespaces: String,
     #[serde(rename = "o")]
     pub operation: Bson,
+    /// Serialized `operation` (see `serialize_payload`).
+    #[serde(skip)]
+    pub payload: String,
+    #[serde(rename = "p")]
+    pub primary: bool,
     #[serde(rename = "t")]
     pub timestamp: Timestamp,
+    /// Transaction identifiers (`lsid` and `txnNumber`), if any.
+    #[serde(default, rename = "x")]
+    pub transaction: Option<Bson>,
 }
 
 impl Event {
-    pub fn debug(&self) {
-        let Self {
-            collection,
-            db,
-            document_id,
-            namespaces,
-            operation,
-            ..
-        } = self;
-
-        let ejson = operation.clone().into_ejson();
-        println!("{db}.{collection} {ejson}");
-        println!("{db}.{collection}::{document_id} {ejson}");
-        for namespace in namespaces.split(',').filter(|x| !x.is_empty()) {
-            println!("{db}.{namespace}::{collection} {ejson}");
+    /// Redis channels this event is published to.
+    pub fn channels(&self, config: &Config) -> Vec<String> {
+        let mut channels = vec![
+            config.redis_channel_collection.render(self, ""),
+            config.redis_channel_document.render(self, ""),
+        ];
+        for namespace in self.namespaces.split(',').filter(|x| !x.is_empty()) {
+            channels.push(config.redis_channel_namespace.render(self, namespace));
+        }
+
+        channels
+    }
+
+    /// Document (or just its `_id`) published in the payload.
+    pub fn document_mut(&mut self) -> Option<&mut Document> {
+        match &mut self.operation {
+            Bson::Document(operation) => match operation.get_mut("d") {
+                Some(Bson::Document(document)) => Some(document),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Invalidate events are never published, but make the stream reopen.
+    pub fn is_invalidate(&self) -> bool {
+        self.operation
+            .as_document()
+            .and_then(|operation| operation.get_str("e").ok())
+            == Some("invalidate")
+    }
+
+    /// Key used for deduplication. Events expanded from one rename share its
+    /// `event_id`, so theirs include the collection and `_id` too.
+    pub fn deduplication_key(&self) -> String {
+        match self.expansion {
+            Expansion::None => self.event_id.to_string(),
+            Expansion::Last | Expansion::Partial => format!(
+                "{}:{}.{}:{}",
+                self.event_id, self.db, self.collection, self.document_id
+            ),
+        }
+    }
+
+    /// Database and collection of a renamed collection (only for renames).
+    pub fn renamed_to(&self) -> Option<(&str, &str)> {
+        let to = self.operation.as_document()?.get_document("to").ok()?;
+        to.get_str("db").ok().zip(to.get_str("coll").ok())
+    }
+
+    /// Serializes the `operation` into the `payload`. If it is larger than
+    /// `max_payload_size`, the document is replaced with its `_id`, so
+    /// `redis-oplog` fetches it from the database.
+    pub fn serialize_payload(&mut self, config: &Config) {
+        self.payload = self.operation.clone().into_ejson().to_string();
+        let Some(max_payload_size) = config.max_payload_size else {
+            return;
+        };
+
+        if self.payload.len() <= max_payload_size {
+            return;
+        }
+
+        if let Some(document) = self.document_mut().filter(|document| document.len() > 1) {
+            *document = doc! {"_id": document.remove("_id")};
+            PAYLOAD_DOWNGRADE_COUNTER.inc();
+            self.payload = self.operation.clone().into_ejson().to_string();
+        }
+    }
+
+    pub fn debug(&self, config: &Config) {
+        for channel in self.channels(config) {
+            println!("{channel} {}", self.payload);
         }
     }
 }
@@ -0,0 +1,88 @@
+use crate::ejson::Ejson;
+use bson::{oid::ObjectId, Bson};
+use std::fmt::Write;
+
+/// How the `_id`s of a collection are stringified (e.g., in the `::id`
+/// channels). By default, only `ObjectID`s and strings are supported.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IdFormat {
+    /// Canonical (compact, sorted keys) EJSON of any `_id`, e.g., documents.
+    Ejson,
+    /// Numbers (integers, doubles, and decimals) as decimal strings.
+    Number,
+    /// Meteor `ObjectID`s (`idGeneration: 'MONGO'`). Hex strings are
+    /// published as `ObjectID`s too.
+    ObjectId,
+    /// Meteor strings (`idGeneration: 'STRING'`). `ObjectID`s are published
+    /// as hex strings too.
+    String,
+    /// Binaries (UUIDs) as hex strings, hyphenated if they have 16 bytes.
+    Uuid,
+}
+
+impl IdFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "ejson" => Some(Self::Ejson),
+            "number" => Some(Self::Number),
+            "objectid" => Some(Self::ObjectId),
+            "string" => Some(Self::String),
+            "uuid" => Some(Self::Uuid),
+            _ => None,
+        }
+    }
+
+    /// BSON types (`$type` aliases) of the `_id`s handled by this format.
+    /// `None` means that all types are handled.
+    pub const fn types(self) -> Option<&'static [&'static str]> {
+        match self {
+            Self::Ejson => None,
+            Self::Number => Some(&["number"]),
+            Self::ObjectId | Self::String => Some(&["objectId", "string"]),
+            Self::Uuid => Some(&["binData"]),
+        }
+    }
+}
+
+/// Converts the `_id` in the published payload, so Meteor receives the type
+/// it expects. Channels use hex strings in both cases.
+pub fn convert(id: &mut Bson, format: Option<IdFormat>) {
+    let converted = match (format, &*id) {
+        (Some(IdFormat::ObjectId), Bson::String(hex)) => {
+            ObjectId::parse_str(hex).ok().map(Bson::ObjectId)
+        }
+        (Some(IdFormat::String), Bson::ObjectId(object_id)) => {
+            Some(Bson::String(object_id.to_hex()))
+        }
+        _ => None,
+    };
+
+    if let Some(converted) = converted {
+        *id = converted;
+    }
+}
+
+/// Stringifies the `_id` for the channels and `Event.document_id`.
+pub fn stringify(id: &Bson, format: Option<IdFormat>) -> String {
+    match (format, id) {
+        (_, Bson::ObjectId(id)) => id.to_hex(),
+        (_, Bson::String(id)) => id.clone(),
+        (Some(IdFormat::Number), Bson::Decimal128(id)) => id.to_string(),
+        (Some(IdFormat::Number), Bson::Double(id)) => id.to_string(),
+        (Some(IdFormat::Number), Bson::Int32(id)) => id.to_string(),
+        (Some(IdFormat::Number), Bson::Int64(id)) => id.to_string(),
+        (Some(IdFormat::Uuid), Bson::Binary(id)) => {
+            let mut hex = String::with_capacity(36);
+            for (index, byte) in id.bytes.iter().enumerate() {
+                if id.bytes.len() == 16 && matches!(index, 4 | 6 | 8 | 10) {
+                    hex.push('-');
+                }
+
+                write!(hex, "{byte:02x}").unwrap();
+            }
+
+            hex
+        }
+        (_, id) => id.clone().into_ejson().to_string(),
+    }
+}
@@ -6,48 +6,360 @@
 #![deny(clippy::style)]
 #![deny(clippy::suspicious)]
 
+mod channel;
+mod checkpoint;
 mod config;
+mod config_file;
 mod ejson;
 mod event;
+mod id;
 mod metrics;
 mod mongo;
 mod redis;
+mod transaction;
 
-use crate::{config::Config, mongo::Mongo, redis::Redis};
+use crate::{
+    checkpoint::Checkpoint,
+    config::Config,
+    event::Event,
+    mongo::{pipelines, Mongo},
+    redis::Redis,
+    transaction::{Progress, Transactions},
+};
+use bson::Bson;
 use metrics::{serve, LAST_EVENT_GAUGE, MONGO_COUNTER, REDIS_COUNTER};
-use std::mem::replace;
+use serde_json::to_string_pretty;
+use std::{collections::VecDeque, env::args, mem::replace, pin::pin, process::exit, sync::Arc};
 use tikv_jemallocator::Jemalloc;
-use tokio::{main, spawn, sync::mpsc::channel};
+use tokio::{
+    main, select,
+    signal::{
+        ctrl_c,
+        unix::{signal, SignalKind},
+    },
+    spawn,
+    sync::{
+        mpsc::{channel, Receiver},
+        watch, Notify,
+    },
+    time::{sleep_until, timeout, Instant},
+};
 
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+const USAGE: &str =
+    "Usage: changestream-to-redis [run | check-config | print-pipeline | ping] [--config <path>]";
+
+enum Command {
+    /// Validates the configuration.
+    CheckConfig,
+    /// Checks whether both MongoDB and Redis are reachable.
+    Ping,
+    /// Prints the change stream pipelines as Extended JSON.
+    PrintPipeline,
+    /// Publishes the MongoDB events to Redis (default).
+    Run,
+}
+
 #[main]
 async fn main() {
-    let mut config = Config::from_env();
-    let mut mongo = Mongo::new(&config).await.unwrap();
+    let (command, config_file) = args_from_env();
+    let config = Config::from_env(config_file).unwrap_or_else(|error| {
+        eprintln!("{error}");
+        exit(1);
+    });
+
+    match command {
+        Command::CheckConfig => println!("Configuration is valid."),
+        Command::Ping => ping(&config).await,
+        Command::PrintPipeline => {
+            let pipelines = Bson::Document(pipelines(&config)).into_relaxed_extjson();
+            println!("{}", to_string_pretty(&pipelines).unwrap());
+        }
+        Command::Run => run(config).await,
+    }
+}
+
+/// Checks both connections, exiting with an error if any of them failed.
+async fn ping(config: &Config) {
+    let mongo = Mongo::ping(config).await;
+    match &mongo {
+        Ok(()) => println!("Mongo: OK"),
+        Err(error) => eprintln!("Mongo: {error}"),
+    }
+
+    let redis = match Redis::new(config).await {
+        Ok(mut redis) => redis.ping().await,
+        Err(error) => Err(error),
+    };
+    match &redis {
+        Ok(()) => println!("Redis: OK"),
+        Err(error) => eprintln!("Redis: {error}"),
+    }
+
+    if mongo.is_err() || redis.is_err() {
+        exit(1);
+    }
+}
+
+async fn run(mut config: Config) {
     let mut redis = Redis::new(&config).await.unwrap();
+    let mut checkpoint = match config.checkpoint.take() {
+        None => None,
+        Some(storage) => Some(Checkpoint::load(storage, &mut redis).await.unwrap()),
+    };
+
+    // Explicit start time takes precedence over the saved checkpoint.
+    let tokens = checkpoint
+        .as_ref()
+        .filter(|_| config.start_at_operation_time.is_none())
+        .map(|checkpoint| checkpoint.tokens().clone())
+        .unwrap_or_default();
+    let mut mongo = Mongo::new(&config, tokens).await.unwrap();
+    let mut progress = mongo.progress();
     let (sender, mut receiver) = channel(config.redis_queue_size);
 
     if let Some(metrics_address) = config.metrics_address.take() {
         spawn(serve(metrics_address));
     }
 
-    spawn(async move {
-        while let Some(event) = mongo.next().await.unwrap() {
+    let config = Arc::new(config);
+    let mongo_config = config.clone();
+    let shutdown = Arc::new(Notify::new());
+    let mongo_shutdown = shutdown.clone();
+    let mongo_task = spawn(async move {
+        loop {
+            // Stop reading new events once the shutdown starts (or on error).
+            // Dropping the `sender` lets the Redis loop drain the queue and
+            // finish.
+            let event = select! {
+                biased;
+                () = mongo_shutdown.notified() => break,
+                event = mongo.next(&mongo_config) => event?,
+            };
+
+            let Some(event) = event else { break };
             LAST_EVENT_GAUGE.set(event.timestamp.time.into());
             MONGO_COUNTER.inc();
             sender.send(event).await.unwrap();
         }
+
+        Ok::<_, mongodb::error::Error>(())
     });
 
-    let batch_size = config.redis_batch_size;
-    let mut batch = Vec::with_capacity(batch_size);
-    while receiver.recv_many(&mut batch, batch_size).await != 0 {
-        REDIS_COUNTER.inc_by(batch.len() as u64);
-        redis
-            .publish(&config, replace(&mut batch, Vec::with_capacity(batch_size)))
-            .await
-            .unwrap();
+    let mut publisher = pin!(async {
+        let batch_size = config.redis_batch_size;
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut next = VecDeque::new();
+        let mut transactions = Transactions::default();
+        while receive(
+            &config,
+            &mut receiver,
+            &mut progress,
+            &mut batch,
+            &mut next,
+            &mut transactions,
+        )
+        .await
+        {
+            // The batch is empty if only transaction markers are pending.
+            if !batch.is_empty() {
+                REDIS_COUNTER.inc_by(batch.len() as u64);
+                if let Some(checkpoint) = &mut checkpoint {
+                    checkpoint.track(&batch).unwrap();
+                }
+
+                redis
+                    .publish(&config, replace(&mut batch, Vec::with_capacity(batch_size)))
+                    .await
+                    .unwrap();
+            }
+
+            redis
+                .publish_transactions(&config, transactions.take_complete(&config))
+                .await
+                .unwrap();
+
+            if let Some(checkpoint) = &mut checkpoint {
+                checkpoint.save(&mut redis).await.unwrap();
+            }
+        }
+    });
+
+    select! {
+        () = &mut publisher => {
+            // The publisher finishes only after the Mongo task, so a fatal
+            // error makes the process exit with a failure (e.g., to restart).
+            if let Err(error) = mongo_task.await.unwrap() {
+                eprintln!("Mongo error: {error:?}");
+                exit(1);
+            }
+
+            return;
+        }
+        () = shutdown_signal() => {}
+    }
+
+    println!("Shutting down...");
+    shutdown.notify_one();
+    match timeout(config.shutdown_timeout, publisher).await {
+        Ok(()) => println!("Shutdown completed."),
+        Err(_) => eprintln!("Shutdown timed out, queued events were not published."),
+    }
+}
+
+/// Parses the command (`run` by default) and the `--config <path>` (or
+/// `--config=<path>`) flag. Exits with the usage on invalid arguments.
+fn args_from_env() -> (Command, Option<String>) {
+    let mut command = None;
+    let mut config_file = None;
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        let next_command = match arg.as_str() {
+            "check-config" => Command::CheckConfig,
+            "ping" => Command::Ping,
+            "print-pipeline" => Command::PrintPipeline,
+            "run" => Command::Run,
+            "--config" => {
+                config_file = Some(args.next().unwrap_or_else(|| usage(1)));
+                continue;
+            }
+            "-h" | "--help" | "help" => usage(0),
+            _ => match arg.strip_prefix("--config=") {
+                Some(path) => {
+                    config_file = Some(path.to_string());
+                    continue;
+                }
+                None => usage(1),
+            },
+        };
+
+        if command.replace(next_command).is_some() {
+            usage(1);
+        }
+    }
+
+    (command.unwrap_or(Command::Run), config_file)
+}
+
+fn usage(code: i32) -> ! {
+    if code == 0 {
+        println!("{USAGE}");
+    } else {
+        eprintln!("{USAGE}");
+    }
+
+    exit(code);
+}
+
+/// Receives the next batch of events, starting with the ones deferred in
+/// `next`. All events of the transactions in the batch are included (even if
+/// the batch gets larger than `redis_batch_size`), unless they do not arrive
+/// in `transaction_timeout`. Later events received in the meantime are
+/// deferred to `next`. The batch is empty if there are no new events, but
+/// some transactions got complete (i.e., their markers are pending). Returns
+/// `false` once there are no more events.
+async fn receive(
+    config: &Config,
+    receiver: &mut Receiver<Event>,
+    progress: &mut watch::Receiver<Progress>,
+    batch: &mut Vec<Event>,
+    next: &mut VecDeque<Event>,
+    transactions: &mut Transactions,
+) -> bool {
+    let deferred = next.len().min(config.redis_batch_size);
+    batch.extend(next.drain(..deferred));
+    if batch.is_empty() {
+        let markers = config.transaction_marker_channel.is_some();
+        loop {
+            select! {
+                biased;
+                count = receiver.recv_many(batch, config.redis_batch_size) => {
+                    if count == 0 {
+                        return false;
+                    }
+
+                    break;
+                }
+                Ok(()) = progress.changed(), if markers && transactions.has_open() => {
+                    transactions.advance(*progress.borrow_and_update());
+                    receive_queued(config, receiver, batch);
+                    if !batch.is_empty() || transactions.has_complete(config) {
+                        break;
+                    }
+                }
+            }
+        }
+    } else {
+        receive_queued(config, receiver, batch);
+    }
+
+    // Deferred events were tracked already.
+    for event in &batch[deferred..] {
+        transactions.track(event);
+    }
+
+    let latest = batch
+        .iter()
+        .filter(|event| event.transaction.is_some())
+        .map(|event| event.timestamp)
+        .max();
+    let Some(latest) = latest else {
+        return true;
+    };
+
+    // Events up to the latest transaction belong to this batch (some of them
+    // may come from the other change stream).
+    let deadline = Instant::now() + config.transaction_timeout;
+    while !transactions.is_complete(config, latest) {
+        let mut events = vec![];
+        select! {
+            biased;
+            event = receiver.recv() => match event {
+                Some(event) => events.push(event),
+                None => break,
+            },
+            // Events up to the progress were queued before it changed.
+            Ok(()) = progress.changed() => {
+                transactions.advance(*progress.borrow_and_update());
+                while let Ok(event) = receiver.try_recv() {
+                    events.push(event);
+                }
+            }
+            // Incomplete transactions are published anyway, but their markers
+            // wait until they are complete.
+            () = sleep_until(deadline) => break,
+        }
+
+        for event in events {
+            transactions.track(&event);
+            if event.timestamp <= latest {
+                batch.push(event);
+            } else {
+                next.push_back(event);
+            }
+        }
+    }
+
+    true
+}
+
+/// Fills the `batch` with the already queued events (up to `redis_batch_size`).
+fn receive_queued(config: &Config, receiver: &mut Receiver<Event>, batch: &mut Vec<Event>) {
+    while batch.len() < config.redis_batch_size {
+        let Ok(event) = receiver.try_recv() else {
+            break;
+        };
+        batch.push(event);
+    }
+}
+
+/// Resolves once the process receives either SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    select! {
+        result = ctrl_c() => result.unwrap(),
+        _ = terminate.recv() => {}
     }
 }
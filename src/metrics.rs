@@ -20,6 +20,27 @@ pub static MONGO_COUNTER: LazyLock<IntCounter> = LazyLock::new(|| {
     )
     .unwrap()
 });
+pub static MONGO_RECONNECT_COUNTER: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "changestream_to_redis_mongo_reconnects_total",
+        "Number of MongoDB reconnection attempts"
+    )
+    .unwrap()
+});
+pub static MONGO_RECONNECT_FAILURE_COUNTER: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "changestream_to_redis_mongo_reconnect_failures_total",
+        "Number of failed MongoDB reconnection attempts"
+    )
+    .unwrap()
+});
+pub static PAYLOAD_DOWNGRADE_COUNTER: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "changestream_to_redis_payload_downgrades_total",
+        "Number of payloads downgraded to the ID-only form"
+    )
+    .unwrap()
+});
 pub static REDIS_COUNTER: LazyLock<IntCounter> = LazyLock::new(|| {
     register_int_counter!(
         "changestream_to_redis_redis_events_total",
@@ -1,50 +1,454 @@
-use crate::{config::Config, event::Event};
-use bson::doc;
+use crate::{
+    checkpoint::ResumeTokens,
+    config::Config,
+    event::{Event, Expansion},
+    id::{convert, stringify, IdFormat},
+    metrics::{MONGO_RECONNECT_COUNTER, MONGO_RECONNECT_FAILURE_COUNTER},
+    transaction::Progress,
+};
+use bson::{bson, doc, serialize_to_bson, Bson, Document, Timestamp};
 use futures_util::StreamExt;
 use mongodb::{
     action::{Action, Watch},
-    change_stream::ChangeStream,
-    error::Error,
+    change_stream::{event::ResumeToken, ChangeStream},
+    error::{CommandError, Error, ErrorKind},
     options::{FullDocumentBeforeChangeType, FullDocumentType},
-    Client,
+    Client, Cursor,
+};
+use std::{mem::replace, time::Duration};
+use tokio::{
+    sync::watch::{Receiver, Sender},
+    time::sleep,
 };
 
+/// Namespace generated from the values of one (or more, if composite) field
+/// paths in the documents of a collection.
+pub struct Namespace {
+    pub collection: String,
+    pub label: String,
+    pub paths: Vec<String>,
+}
+
+/// Top-level fields to keep in (or remove from) the full documents.
+pub enum Projection {
+    Exclude(Vec<String>),
+    Include(Vec<String>),
+}
+
+/// How often the resume tokens are checked (see `Mongo::progress`) while
+/// waiting for events, e.g., against the `end_at_operation_time`.
+const PROGRESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Server error codes after which a change stream can be resumed. See the
+/// [change streams specification](https://github.com/mongodb/specifications/blob/master/source/change-streams/change-streams.md#resumable-error).
+const RESUMABLE_ERROR_CODES: [i32; 17] = [
+    6, 7, 43, 63, 89, 91, 133, 150, 189, 234, 262, 9001, 10107, 11600, 11602, 13435, 13436,
+];
+
 pub struct Mongo {
+    client: Client,
+    /// Whether the primary stream reached the `end_at_operation_time`.
+    primary_ended: bool,
+    /// Whether the secondary stream reached the `end_at_operation_time`.
+    secondary_ended: bool,
+    progress: Sender<Progress>,
+    /// Rename being expanded, if any.
+    rename: Option<Rename>,
     stream1: ChangeStream<Event>,
     stream2: Option<ChangeStream<Event>>,
+    /// Resume tokens of the last delivered events (or batches, see `reconnect`),
+    /// used for reopening and reconnecting.
+    tokens: ResumeTokens,
 }
 
 impl Mongo {
-    pub async fn new(config: &Config) -> Result<Self, Error> {
+    pub async fn new(config: &Config, tokens: ResumeTokens) -> Result<Self, Error> {
         let client = Client::with_uri_str(config.mongo_url.as_str()).await?;
-        let stream1 = create_change_stream(&client, config, true).await?;
-        let stream2 = match &config.full_document_collections {
-            None => None,
-            Some(_) => Some(create_change_stream(&client, config, false).await?),
-        };
+        let (stream1, stream2) = create_change_streams(&client, config, &tokens).await?;
 
         println!("Mongo connection initialized.");
-        Ok(Self { stream1, stream2 })
+        Ok(Self {
+            client,
+            primary_ended: false,
+            secondary_ended: false,
+            progress: Sender::new(Progress::default()),
+            rename: None,
+            stream1,
+            stream2,
+            tokens,
+        })
+    }
+
+    /// Checks whether MongoDB is reachable.
+    pub async fn ping(config: &Config) -> Result<(), Error> {
+        let client = Client::with_uri_str(config.mongo_url.as_str()).await?;
+        client
+            .database("admin")
+            .run_command(doc! {"ping": 1})
+            .await?;
+        Ok(())
+    }
+
+    /// Receives the `Progress` of both change streams. It is updated only in
+    /// `next`, so all events up to it were sent before it changes.
+    pub fn progress(&self) -> Receiver<Progress> {
+        self.progress.subscribe()
     }
 
-    /// Polls the next `Event` from either of change streams.
-    pub async fn next(&mut self) -> Result<Option<Event>, Error> {
-        let Self { stream1, stream2 } = self;
-        match stream2 {
-            None => stream1.next().await.transpose(),
+    /// Polls the next `Event` from either of change streams. Resumable errors
+    /// trigger a reconnection (see `reconnect`). All previously returned
+    /// events have to be handled (e.g., sent) before calling it again.
+    pub async fn next(&mut self, config: &Config) -> Result<Option<Event>, Error> {
+        loop {
+            self.update_progress();
+            if let Some(rename) = &mut self.rename {
+                match rename.next(config).await? {
+                    Some(event) => return Ok(Some(event)),
+                    None => self.rename = None,
+                }
+            }
+
+            // Quiet (or heavily filtered) streams may have no events for a
+            // long time, so their post-batch resume tokens are checked
+            // periodically, e.g., to detect the `end_at_operation_time`.
+            let result = tokio::select! {
+                result = self.poll(config) => result,
+                () = sleep(PROGRESS_CHECK_INTERVAL) => {
+                    let end = config.end_at_operation_time;
+                    if end.is_some_and(|end| self.check_end(end)) {
+                        println!("Mongo change streams reached the end time.");
+                        return Ok(None);
+                    }
+
+                    continue;
+                }
+            };
+
+            match result {
+                Err(error) if is_resumable(&error) => self.reconnect(config, error).await?,
+                // Events after the `end_at_operation_time` are skipped, and
+                // there are no more events once both streams reach it.
+                Ok(Some(event))
+                    if config
+                        .end_at_operation_time
+                        .is_some_and(|end| event.timestamp > end) =>
+                {
+                    if event.primary {
+                        self.primary_ended = true;
+                    } else {
+                        self.secondary_ended = true;
+                    }
+
+                    if self.has_ended() {
+                        println!("Mongo change streams reached the end time.");
+                        return Ok(None);
+                    }
+                }
+                // Invalidated stream is reopened right after the invalidating event.
+                Ok(Some(event)) if event.is_invalidate() => {
+                    if let Err(error) = self.reopen(config, event.primary).await {
+                        if !is_resumable(&error) {
+                            return Err(error);
+                        }
+
+                        self.reconnect(config, error).await?;
+                    }
+                }
+                // Renamed collection is expanded before the next events.
+                Ok(Some(event)) if event.renamed_to().is_some() => {
+                    self.rename = Some(Rename::new(&self.client, config, event).await?);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Publishes the cluster times of the resume tokens of both streams.
+    fn update_progress(&self) {
+        let progress = Progress {
+            primary: token_time(self.stream1.resume_token()),
+            secondary: token_time(self.stream2.as_ref().and_then(ChangeStream::resume_token)),
+        };
+
+        self.progress
+            .send_if_modified(|current| replace(current, progress) != progress);
+    }
+
+    /// Marks the streams whose resume tokens passed the `end` as ended.
+    fn check_end(&mut self, end: Timestamp) -> bool {
+        if token_time(self.stream1.resume_token()).is_some_and(|time| time > end) {
+            self.primary_ended = true;
+        }
+
+        let stream2 = self.stream2.as_ref();
+        if token_time(stream2.and_then(ChangeStream::resume_token)).is_some_and(|time| time > end) {
+            self.secondary_ended = true;
+        }
+
+        self.has_ended()
+    }
+
+    /// Whether all of the streams reached the `end_at_operation_time`.
+    const fn has_ended(&self) -> bool {
+        self.primary_ended && (self.secondary_ended || self.stream2.is_none())
+    }
+
+    async fn poll(&mut self, config: &Config) -> Result<Option<Event>, Error> {
+        let Self {
+            stream1,
+            stream2,
+            tokens,
+            ..
+        } = self;
+        let mut event = match stream2 {
+            None => stream1.next().await.transpose()?,
             Some(stream2) => tokio::select! {
                 biased;
-                event = stream1.next() => event.transpose(),
-                event = stream2.next() => event.transpose(),
+                event = stream1.next() => event.transpose()?,
+                event = stream2.next() => event.transpose()?,
             },
+        };
+
+        match (&event, stream2) {
+            (Some(event), Some(stream2)) if !event.primary => {
+                tokens.secondary = stream2.resume_token();
+            }
+            (Some(_), _) => tokens.primary = stream1.resume_token(),
+            (None, _) => {}
+        }
+
+        if let Some(event) = &mut event {
+            identify(config, event);
+        }
+
+        Ok(event)
+    }
+
+    /// Recreates an invalidated change stream using `startAfter`.
+    async fn reopen(&mut self, config: &Config, primary: bool) -> Result<(), Error> {
+        println!("Mongo change stream invalidated, reopening.");
+        if primary {
+            let token = self.tokens.primary.clone();
+            self.stream1 = create_change_stream(&self.client, config, true, token).await?;
+        } else {
+            let token = self.tokens.secondary.clone();
+            self.stream2 = Some(create_change_stream(&self.client, config, false, token).await?);
+        }
+
+        Ok(())
+    }
+
+    /// Recreates both change streams from their last resume tokens, with an
+    /// exponential backoff between the attempts.
+    async fn reconnect(&mut self, config: &Config, mut error: Error) -> Result<(), Error> {
+        // Post-batch resume tokens are newer than the ones of the last
+        // delivered events, and they are the only progress quiet (or heavily
+        // filtered) streams make.
+        if let Some(token) = self.stream1.resume_token() {
+            self.tokens.primary = Some(token);
+        }
+
+        if let Some(token) = self.stream2.as_ref().and_then(ChangeStream::resume_token) {
+            self.tokens.secondary = Some(token);
+        }
+
+        for retry in 1..=config.mongo_reconnect_retry_count {
+            eprintln!("Mongo error (retry #{retry}): {error:?}");
+            let delay = Duration::from_millis(100) * 2_u32.saturating_pow(retry - 1);
+            sleep(delay.min(config.mongo_reconnect_max_delay)).await;
+
+            MONGO_RECONNECT_COUNTER.inc();
+            match create_change_streams(&self.client, config, &self.tokens).await {
+                Ok((stream1, stream2)) => {
+                    eprintln!("Mongo reconnection succeeded (retry #{retry})");
+                    self.stream1 = stream1;
+                    self.stream2 = stream2;
+                    return Ok(());
+                }
+                Err(next_error) => {
+                    MONGO_RECONNECT_FAILURE_COUNTER.inc();
+                    if !is_resumable(&next_error) {
+                        return Err(next_error);
+                    }
+
+                    error = next_error;
+                }
+            }
+        }
+
+        Err(error)
+    }
+}
+
+/// Rename being expanded into a removal from the old collection and an
+/// insertion into the new one for each of its documents, as
+/// `cultofcoders:redis-oplog` has no event for a whole collection. The `_id`s
+/// are read from the new collection, so later changes may be reflected too.
+struct Rename {
+    cursor: Cursor<Document>,
+    /// The rename event itself, used as a template of the expanded ones.
+    event: Event,
+    /// `_id` of the next document, read ahead to know which one is the last.
+    id: Option<Bson>,
+    /// Insertion expanded together with the last returned removal.
+    insertion: Option<Event>,
+    /// Database and name of the new collection.
+    to: (String, String),
+}
+
+impl Rename {
+    async fn new(client: &Client, config: &Config, event: Event) -> Result<Self, Error> {
+        let (db, collection) = event.renamed_to().expect("Only renames can be expanded");
+        let to = (db.to_string(), collection.to_string());
+
+        // Only the `_id`s handled in `cultofcoders:redis-oplog` (see `ids_filter`).
+        let mut filter = doc! {};
+        let format = id_format(config, &to.1).unwrap_or(IdFormat::ObjectId);
+        if let Some(types) = format.types() {
+            filter.insert("_id", doc! {"$type": types});
         }
+
+        let mut cursor = client
+            .database(&to.0)
+            .collection::<Document>(&to.1)
+            .find(filter)
+            .projection(doc! {"_id": 1})
+            .sort(doc! {"_id": 1})
+            .await?;
+        let id = next_id(&mut cursor).await?;
+
+        Ok(Self {
+            cursor,
+            event,
+            id,
+            insertion: None,
+            to,
+        })
     }
+
+    /// Returns the next expanded event, if any.
+    async fn next(&mut self, config: &Config) -> Result<Option<Event>, Error> {
+        if let Some(insertion) = self.insertion.take() {
+            return Ok(Some(insertion));
+        }
+
+        let Some(id) = self.id.take() else {
+            return Ok(None);
+        };
+
+        self.id = next_id(&mut self.cursor).await?;
+        let expansion = if self.id.is_none() {
+            Expansion::Last
+        } else {
+            Expansion::Partial
+        };
+
+        self.insertion = Some(self.expand(config, false, id.clone(), expansion));
+        Ok(Some(self.expand(config, true, id, Expansion::Partial)))
+    }
+
+    fn expand(&self, config: &Config, removal: bool, id: Bson, expansion: Expansion) -> Event {
+        let (db, collection) = if removal {
+            (self.event.db.clone(), self.event.collection.clone())
+        } else {
+            self.to.clone()
+        };
+
+        let mut event = Event {
+            collection,
+            db,
+            document_id: String::new(),
+            document_key: Some(id.clone()),
+            event_id: self.event.event_id.clone(),
+            expansion,
+            namespaces: String::new(),
+            operation: bson!({"e": if removal { "r" } else { "i" }, "d": {"_id": id}, "f": []}),
+            payload: String::new(),
+            primary: self.event.primary,
+            timestamp: self.event.timestamp,
+            transaction: None,
+        };
+
+        identify(config, &mut event);
+        event
+    }
+}
+
+async fn next_id(cursor: &mut Cursor<Document>) -> Result<Option<Bson>, Error> {
+    let document = cursor.next().await.transpose()?;
+    Ok(document.and_then(|mut document| document.remove("_id")))
+}
+
+/// Stringifies the `_id` for the channels and converts the published one (see
+/// `id_formats`).
+fn identify(config: &Config, event: &mut Event) {
+    let format = id_format(config, &event.collection);
+    if let Some(id) = &event.document_key {
+        event.document_id = stringify(id, format);
+    }
+
+    if let Some(id) = event
+        .document_mut()
+        .and_then(|document| document.get_mut("_id"))
+    {
+        convert(id, format);
+    }
+}
+
+fn id_format(config: &Config, collection: &str) -> Option<IdFormat> {
+    let formats = config.id_formats.as_ref();
+    formats.and_then(|formats| formats.get(collection).copied())
+}
+
+/// Cluster time of a resume token. Its `_data` starts with the timestamp type
+/// byte (`82`), followed by the big-endian seconds and increment.
+fn token_time(token: Option<ResumeToken>) -> Option<Timestamp> {
+    let token = serialize_to_bson(&token?).ok()?;
+    let data = token
+        .as_document()?
+        .get_str("_data")
+        .ok()?
+        .strip_prefix("82")?;
+    Some(Timestamp {
+        time: u32::from_str_radix(data.get(0..8)?, 16).ok()?,
+        increment: u32::from_str_radix(data.get(8..16)?, 16).ok()?,
+    })
+}
+
+/// Network errors, server selection errors, and a handful of server errors
+/// (e.g., after a failover) are resumable. All other errors are fatal.
+fn is_resumable(error: &Error) -> bool {
+    error.contains_label("ResumableChangeStreamError")
+        || match error.kind.as_ref() {
+            ErrorKind::Command(CommandError { code, .. }) => RESUMABLE_ERROR_CODES.contains(code),
+            ErrorKind::ConnectionPoolCleared { .. }
+            | ErrorKind::Io(_)
+            | ErrorKind::ServerSelection { .. } => true,
+            _ => false,
+        }
+}
+
+async fn create_change_streams(
+    client: &Client,
+    config: &Config,
+    tokens: &ResumeTokens,
+) -> Result<(ChangeStream<Event>, Option<ChangeStream<Event>>), Error> {
+    let stream1 = create_change_stream(client, config, true, tokens.primary.clone()).await?;
+    let stream2 = match &config.full_document_collections {
+        None => None,
+        Some(_) => {
+            Some(create_change_stream(client, config, false, tokens.secondary.clone()).await?)
+        }
+    };
+
+    Ok((stream1, stream2))
 }
 
 async fn create_change_stream(
     client: &Client,
     config: &Config,
     primary: bool,
+    token: Option<ResumeToken>,
 ) -> Result<ChangeStream<Event>, Error> {
     // Only the primary stream will receive full documents, and only if the `full_document` is set.
     // However, as `namespace_fields` requires the field values to work, it implies `full_document`
@@ -53,118 +457,516 @@ async fn create_change_stream(
         .then(|| config.full_document.clone())
         .flatten()
         .or_else(|| {
-            config
-                .namespaces
-                .is_some()
+            (config.filters.is_some() || config.namespaces.is_some())
                 .then_some(FullDocumentType::UpdateLookup)
         });
 
-    client
-        .default_database()
-        .expect("MONGO_URL is missing default database")
-        .watch()
+    let database = client.default_database();
+    let watch = if config.watch_deployment
+        || config.databases.is_some()
+        || config.excluded_databases.is_some()
+    {
+        client.watch()
+    } else {
+        database
+            .as_ref()
+            .expect("MONGO_URL is missing default database")
+            .watch()
+    };
+
+    watch
         .pipeline(create_pipeline(config, primary))
         .optional(config.mongo_batch_size, Watch::batch_size)
         .optional(full_document, Watch::full_document)
         .optional(
-            config
-                .namespaces
-                .is_some()
+            (config.filters.is_some() || config.namespaces.is_some())
                 .then_some(FullDocumentBeforeChangeType::WhenAvailable),
             Watch::full_document_before_change,
         )
         .optional(config.mongo_max_await_time, Watch::max_await_time)
+        .optional(
+            config.start_at_operation_time.filter(|_| token.is_none()),
+            Watch::start_at_operation_time,
+        )
+        .optional(token, Watch::start_after)
         .await
         .map(ChangeStream::with_type)
 }
 
-fn create_pipeline(config: &Config, primary: bool) -> [bson::Document; 2] {
+/// Pipelines of both change streams (the secondary one only if it is used).
+pub fn pipelines(config: &Config) -> Document {
+    let mut pipelines = doc! {"primary": create_pipeline(config, true)};
+    if config.full_document_collections.is_some() {
+        pipelines.insert("secondary", create_pipeline(config, false));
+    }
+
+    pipelines
+}
+
+fn create_pipeline(config: &Config, primary: bool) -> [Document; 2] {
     // Filter events that...
-    // 1. We actually can process, i.e., their `_id` is handled in `cultofcoders:redis-oplog`.
-    // 2. We are interested in, i.e., `cultofcoders:redis-oplog` is interested in.
-    let mut query = doc! {
-        "documentKey._id": {"$type": ["objectId", "string"]},
-        "operationType": {"$in": ["delete", "insert", "replace", "update"]},
-    };
+    // 1. We actually can process, i.e., their `_id` is handled in `cultofcoders:redis-oplog` (or
+    //    configured in `id_formats` for their collection).
+    // 2. We are interested in, i.e., `cultofcoders:redis-oplog` is interested in. Renamed
+    //    collections have no documents, so they are expanded into events of all documents of
+    //    the new collection (see `Rename`). Dropped collections (and databases) are not, as
+    //    their documents are gone.
+    let mut query = doc! {"$or": [
+        {
+            "$or": ids_filter(config),
+            "operationType": {"$in": ["delete", "insert", "replace", "update"]},
+        },
+        {"operationType": "rename"},
+    ]};
 
-    // 3. Match the collection filters if there's any.
-    if let Some(names) = config.excluded_collections.clone() {
-        query.insert("ns.coll", doc! { "$nin": names });
+    // 3. Match the operation types configured per collection. All other
+    // collections receive all of the supported operation types.
+    let mut conditions = vec![];
+    if let Some(operation_types) = &config.operation_types {
+        let mut branches: Vec<_> = operation_types
+            .iter()
+            .map(
+                |(collection, names)| doc! {"ns.coll": collection, "operationType": {"$in": names}},
+            )
+            .collect();
+        branches.push(doc! {"ns.coll": {"$nin": operation_types.keys().collect::<Vec<_>>()}});
+        conditions.push(doc! {"$or": branches});
     }
 
-    if let Some(names) = config.full_document_collections.clone() {
-        let operator = if primary { "$in" } else { "$nin" };
-        query.insert("ns.coll", doc! { operator: names });
+    // 4. Match the database filters if there's any.
+    let mut databases = doc! {};
+    if let Some(names) = config.databases.clone() {
+        databases.insert("$in", names);
     }
 
-    // There are two streams -- primary and secondary. The former receives whole documents if they
-    // are requested (`full_document` is set) or simply available (`full_document_collections` is
-    // set; only `insert` will have it without `full_document` set).
-    let mut document = doc! {"_id": "$documentKey._id"};
-    if primary && (config.full_document.is_some() || config.full_document_collections.is_some()) {
-        document = doc! {"$ifNull": ["$fullDocument", {"$ifNull": ["$fullDocumentBeforeChange", {"_id": document}]}]};
+    if let Some(names) = config.excluded_databases.clone() {
+        databases.insert("$nin", names);
     }
 
-    // Comma separated list of namespaces (including array flattening).
-    let ns = config.namespaces.iter().flatten().fold(
-        doc! {"$literal": ""},
-        |initial_value, (collection, field)| {
-            let namespace = format!("{field}::");
-            let next_value = format!("$fullDocument.{field}");
-            let prev_value = format!("$fullDocumentBeforeChange.{field}");
-            doc! {"$reduce": {
-                "input": {"$cond": {
-                    "if": {"$eq": ["$ns.coll", collection]},
-                    "then": {"$let": {
-                        "vars": {"v": {"$ifNull": [next_value, {"$ifNull": [prev_value, []]}]}},
-                        "in": {"$switch": {
-                            "branches": [
-                                // Arrays are flattened.
-                                {
-                                    "case": {"$eq": [{"$type": "$$v"}, "array"]},
-                                    "then": "$$v"
-                                },
-                                // Objects are mapped to their keys.
-                                {
-                                    "case": {"$eq": [{"$type": "$$v"}, "object"]},
-                                    "then": {"$map": {
-                                        "input": {"$objectToArray": "$$v"},
-                                        "in": "$$this.k"
-                                    }}
-                                },
-                            ],
-                            // Other values are left as-is.
-                            "default": ["$$v"]
-                        }}
-                    }},
-                    "else": []
-                }},
-                "initialValue": initial_value,
-                "in": {"$concat": ["$$value", ",", namespace, {"$toString": "$$this"}]}
-            }}
-        },
-    );
+    if !databases.is_empty() {
+        query.insert("ns.db", databases);
+    }
+
+    // 5. Match the collection filters if there's any.
+    if let Some(names) = &config.included_collections {
+        conditions.push(collections_filter(names, true));
+    }
+
+    if let Some(names) = &config.excluded_collections {
+        conditions.push(collections_filter(names, false));
+    }
+
+    if let Some(names) = &config.full_document_collections {
+        conditions.push(collections_filter(names, primary));
+    }
+
+    // 6. Match the document filters if there's any. Changes without the
+    // pre-image (other than inserts) are let through, as the document may have
+    // left the filtered set (e.g., pre-images are not enabled).
+    if let Some(filters) = &config.filters {
+        let mut branches: Vec<_> = filters
+            .iter()
+            .map(|(collection, filter)| {
+                doc! {
+                    "ns.coll": collection,
+                    "$or": [
+                        prefix_filter(filter, "fullDocument"),
+                        prefix_filter(filter, "fullDocumentBeforeChange"),
+                        {"fullDocumentBeforeChange": null, "operationType": {"$ne": "insert"}},
+                    ],
+                }
+            })
+            .collect();
+        branches.push(doc! {"ns.coll": {"$nin": filters.keys().collect::<Vec<_>>()}});
+        conditions.push(doc! {"$or": branches});
+    }
+
+    if !conditions.is_empty() {
+        query.insert("$and", conditions);
+    }
+
+    // 7. Invalidated streams are reopened, so these events skip all filters.
+    let query = doc! {"$or": [{"operationType": "invalidate"}, query]};
 
     [
         doc! {"$match": query},
         doc! {"$project": {
             "c": "$ns.coll",
             "d": "$ns.db",
-            // The ID is stringified to support `ObjectID`s.
-            "i": {"$toString": "$documentKey._id"},
-            "n": ns,
+            // The ID is stringified later, as it depends on the collection.
+            "i": "$documentKey._id",
+            "n": namespaces_expression(config),
             "o": {
                 "e": {"$switch": {
                     "branches": [
                         {"case": {"$eq": ["$operationType", "delete"]}, "then": "r"},
-                        {"case": {"$eq": ["$operationType", "insert"]}, "then": "i"}
+                        {"case": {"$eq": ["$operationType", "insert"]}, "then": "i"},
+                        {"case": {"$in": ["$operationType", ["invalidate", "rename"]]}, "then": "$operationType"}
                     ],
                     "default": "u"
                 }},
-                "d": document,
-                "f": []
+                "d": document_expression(config, primary),
+                "f": fields_expression(),
+                // Only renames have it.
+                "to": "$to"
             },
-            "t": "$clusterTime"
+            // Used to checkpoint both streams separately.
+            "p": {"$literal": primary},
+            "t": "$clusterTime",
+            // Used to keep the events of one transaction together.
+            "x": {"$cond": {
+                "if": {"$eq": [{"$type": "$txnNumber"}, "missing"]},
+                "then": "$$REMOVE",
+                "else": {"lsid": "$lsid", "txnNumber": "$txnNumber"}
+            }}
         }},
     ]
 }
+
+/// Published document, i.e., either the `_id` only or the whole (projected)
+/// document.
+fn document_expression(config: &Config, primary: bool) -> Document {
+    // There are two streams -- primary and secondary. The former receives whole documents if they
+    // are requested (`full_document` is set) or simply available (`full_document_collections` is
+    // set; only `insert` will have it without `full_document` set).
+    let mut document = doc! {"_id": "$documentKey._id"};
+    if primary && (config.full_document.is_some() || config.full_document_collections.is_some()) {
+        document = doc! {"$ifNull": ["$fullDocument", {"$ifNull": ["$fullDocumentBeforeChange", {"_id": document}]}]};
+
+        // Projections are applied to whole documents only. Each collection
+        // has its own branch and all other ones are left as-is.
+        if let Some(projections) = config.projections.as_ref().filter(|x| !x.is_empty()) {
+            let branches: Vec<_> = projections
+                .iter()
+                .map(|(collection, projection)| {
+                    let (fields, operator) = match projection {
+                        Projection::Exclude(fields) => (fields.clone(), "$not"),
+                        Projection::Include(fields) => {
+                            ([fields.clone(), vec!["_id".to_string()]].concat(), "$and")
+                        }
+                    };
+
+                    doc! {
+                        "case": {"$eq": ["$ns.coll", collection]},
+                        "then": {"$arrayToObject": {"$filter": {
+                            "input": {"$objectToArray": "$$d"},
+                            "cond": {operator: [{"$in": ["$$this.k", fields]}]}
+                        }}}
+                    }
+                })
+                .collect();
+            document = doc! {"$let": {
+                "vars": {"d": document},
+                "in": {"$switch": {"branches": branches, "default": "$$d"}}
+            }};
+        }
+    }
+
+    document
+}
+
+/// Filters of the `_id`s that can be stringified. `ObjectID` and string
+/// `_id`s are always accepted, and other types only in the collections with
+/// the matching `id_formats`.
+fn ids_filter(config: &Config) -> Vec<Document> {
+    let mut ids = vec![doc! {"documentKey._id": {"$type": ["objectId", "string"]}}];
+    if let Some(id_formats) = &config.id_formats {
+        for format in [IdFormat::Ejson, IdFormat::Number, IdFormat::Uuid] {
+            let collections: Vec<_> = id_formats
+                .iter()
+                .filter(|(_, x)| **x == format)
+                .map(|(collection, _)| collection)
+                .collect();
+            if collections.is_empty() {
+                continue;
+            }
+
+            let mut id = doc! {"ns.coll": {"$in": collections}};
+            if let Some(types) = format.types() {
+                id.insert("documentKey._id", doc! {"$type": types});
+            }
+
+            ids.push(id);
+        }
+    }
+
+    ids
+}
+
+/// Comma separated list of namespaces (including array flattening).
+fn namespaces_expression(config: &Config) -> Document {
+    config
+        .namespaces
+        .iter()
+        .flatten()
+        .fold(doc! {"$literal": ""}, |initial_value, namespace| {
+            let label = format!("{}::", namespace.label);
+            let values = if config.namespaces_from_both_images {
+                doc! {"$setUnion": [
+                    namespace_values(&namespace.paths, Some("fullDocument")),
+                    namespace_values(&namespace.paths, Some("fullDocumentBeforeChange")),
+                ]}
+            } else {
+                namespace_values(&namespace.paths, None)
+            };
+
+            doc! {"$reduce": {
+                "input": {"$cond": {
+                    "if": {"$eq": ["$ns.coll", &namespace.collection]},
+                    "then": values,
+                    "else": []
+                }},
+                "initialValue": initial_value,
+                "in": {"$concat": ["$$value", ",", label, "$$this"]}
+            }}
+        })
+}
+
+/// Top-level names of all modified fields (only for updates).
+fn fields_expression() -> Document {
+    doc! {"$cond": {
+        "if": {"$eq": ["$operationType", "update"]},
+        "then": {"$setUnion": [{"$map": {
+            "input": {"$concatArrays": [
+                {"$map": {
+                    "input": {"$objectToArray": "$updateDescription.updatedFields"},
+                    "in": "$$this.k"
+                }},
+                "$updateDescription.removedFields",
+                {"$map": {
+                    "input": {"$ifNull": ["$updateDescription.truncatedArrays", []]},
+                    "in": "$$this.field"
+                }}
+            ]},
+            "in": {"$arrayElemAt": [{"$split": ["$$this", "."]}, 0]}
+        }}]},
+        "else": []
+    }}
+}
+
+/// Stringified values of a namespace. Composite namespaces join the values of
+/// all of their paths with `:` (each combination, if there are many).
+fn namespace_values(paths: &[String], image: Option<&str>) -> Document {
+    let mut paths = paths.iter().map(|path| {
+        doc! {"$map": {"input": path_values(path, image), "in": {"$toString": "$$this"}}}
+    });
+    let initial_value = paths.next().expect("Namespace has no paths.");
+    paths.fold(initial_value, |values, next_values| {
+        doc! {"$reduce": {
+            "input": values,
+            "initialValue": [],
+            "in": {"$concatArrays": ["$$value", {"$map": {
+                "input": next_values,
+                "as": "next",
+                "in": {"$concat": ["$$this", ":", "$$next"]}
+            }}]}
+        }}
+    })
+}
+
+/// Values of a (possibly nested) field path. Paths through arrays of
+/// subdocuments (e.g., `members.userId`) resolve to arrays. Values are taken
+/// from the given `image`, or from the post-image with a fallback to the
+/// pre-image if there is none.
+fn path_values(path: &str, image: Option<&str>) -> Document {
+    let value = image.map_or_else(
+        || {
+            let next_value = format!("$fullDocument.{path}");
+            let prev_value = format!("$fullDocumentBeforeChange.{path}");
+            doc! {"$ifNull": [next_value, {"$ifNull": [prev_value, []]}]}
+        },
+        |image| doc! {"$ifNull": [format!("${image}.{path}"), []]},
+    );
+
+    doc! {"$let": {
+        "vars": {"v": value},
+        "in": {"$switch": {
+            "branches": [
+                // Arrays are flattened.
+                {
+                    "case": {"$eq": [{"$type": "$$v"}, "array"]},
+                    "then": "$$v"
+                },
+                // Objects are mapped to their keys.
+                {
+                    "case": {"$eq": [{"$type": "$$v"}, "object"]},
+                    "then": {"$map": {
+                        "input": {"$objectToArray": "$$v"},
+                        "in": "$$this.k"
+                    }}
+                },
+            ],
+            // Other values are left as-is.
+            "default": ["$$v"]
+        }}
+    }}
+}
+
+/// Matches `ns.coll` against a list of collection names, globs (e.g.,
+/// `audit_*`), and regular expressions (e.g., `/^audit_\d+$/`). Names are
+/// matched with `$in` and patterns with `$regexMatch`.
+fn collections_filter(names: &[String], matching: bool) -> Document {
+    let mut exact = vec![];
+    let mut filters = vec![];
+    for name in names {
+        let regex = match name.strip_prefix('/').and_then(|x| x.strip_suffix('/')) {
+            Some(regex) => regex.to_string(),
+            None if name.contains(['*', '?']) => glob_to_regex(name),
+            None => {
+                exact.push(name.clone());
+                continue;
+            }
+        };
+
+        filters.push(doc! {"$expr": {"$regexMatch": {"input": "$ns.coll", "regex": regex}}});
+    }
+
+    if !exact.is_empty() {
+        filters.insert(0, doc! {"ns.coll": {"$in": exact}});
+    }
+
+    let operator = if matching { "$or" } else { "$nor" };
+    doc! { operator: filters }
+}
+
+/// Rewrites all field paths in a query filter to be relative to `prefix`.
+/// Only the logical operators (`$and`, `$nor`, and `$or`) are allowed at
+/// the top level, as other ones (e.g., `$expr`) reference fields differently.
+fn prefix_filter(filter: &Document, prefix: &str) -> Document {
+    filter
+        .iter()
+        .map(|(key, value)| match (key.as_str(), value) {
+            ("$and" | "$nor" | "$or", Bson::Array(filters)) => {
+                let filters = filters.iter().map(|filter| match filter {
+                    Bson::Document(filter) => Bson::Document(prefix_filter(filter, prefix)),
+                    _ => filter.clone(),
+                });
+                (key.clone(), Bson::Array(filters.collect()))
+            }
+            _ if key.starts_with('$') => panic!("Filter operator `{key}` is not supported."),
+            _ => (format!("{prefix}.{key}"), value.clone()),
+        })
+        .collect()
+}
+
+/// Checks the syntax of a regular expression, i.e., whether its groups and
+/// character classes are closed, escapes complete, and quantifiers follow
+/// something to repeat. It does not compile it, as only the server does.
+pub fn is_valid_regex(regex: &str) -> bool {
+    let mut chars = regex.chars().peekable();
+    let mut depth = 0_usize;
+    // Whether the previous token can be repeated by a quantifier.
+    let mut repeatable = false;
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => {
+                if chars.next().is_none() {
+                    return false;
+                }
+
+                repeatable = true;
+            }
+            '[' => {
+                // A `]` right after the opening (or negation) is a literal.
+                chars.next_if_eq(&'^');
+                chars.next_if_eq(&']');
+                loop {
+                    match chars.next() {
+                        None => return false,
+                        Some('\\') if chars.next().is_none() => return false,
+                        Some(']') => break,
+                        Some(_) => {}
+                    }
+                }
+
+                repeatable = true;
+            }
+            '(' => {
+                depth += 1;
+                // Group modifiers, e.g., `(?:...)`, are not quantifiers.
+                chars.next_if_eq(&'?');
+                repeatable = false;
+            }
+            ')' => {
+                let Some(next_depth) = depth.checked_sub(1) else {
+                    return false;
+                };
+
+                depth = next_depth;
+                repeatable = true;
+            }
+            '|' => repeatable = false,
+            '*' | '+' | '?' => {
+                if !repeatable {
+                    return false;
+                }
+
+                // Lazy (`*?`) and possessive (`*+`) quantifiers.
+                chars.next_if(|next| matches!(next, '?' | '+'));
+                repeatable = false;
+            }
+            _ => repeatable = true,
+        }
+    }
+
+    depth == 0
+}
+
+/// Translates a glob (`*` and `?` wildcards) into an anchored regular expression.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for char in glob.chars() {
+        match char {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '$' | '(' | ')' | '+' | '.' | '[' | '\\' | ']' | '^' | '{' | '|' | '}' => {
+                regex.push('\\');
+                regex.push(char);
+            }
+            _ => regex.push(char),
+        }
+    }
+
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::deserialize_from_bson;
+
+    fn token(data: &str) -> ResumeToken {
+        deserialize_from_bson(Bson::Document(doc! {"_data": data})).unwrap()
+    }
+
+    #[test]
+    fn token_time_of_event() {
+        // Resume token of an `insert` from the MongoDB documentation.
+        let token = token("82635019A0000000012B042C0100296E5A1004AB1154ACACD849A48C61756D70D3B21F463C6F7065726174696F6E54797065003C696E736572740046646F63756D656E744B65790046645F696400646350199FD6BCD6DC3AA7E57500000004");
+        let time = Timestamp {
+            time: 1_666_193_824,
+            increment: 1,
+        };
+        assert_eq!(token_time(Some(token)), Some(time));
+    }
+
+    #[test]
+    fn token_time_of_high_water_mark() {
+        let time = Timestamp {
+            time: 0x6530_A1B2,
+            increment: 10,
+        };
+        assert_eq!(
+            token_time(Some(token("826530A1B20000000A2B0229296E04"))),
+            Some(time)
+        );
+    }
+
+    #[test]
+    fn token_time_of_invalid_token() {
+        assert_eq!(token_time(None), None);
+        assert_eq!(token_time(Some(token(""))), None);
+        assert_eq!(token_time(Some(token("8263"))), None);
+    }
+}
@@ -1,95 +1,582 @@
 use crate::{ejson::Ejson, event::Event, Config};
-use redis::{aio::ConnectionManager, Client, RedisError, Script};
+use bson::Bson;
+use futures_util::future::{join_all, try_join_all};
+use redis::{
+    aio::{ConnectionLike, ConnectionManager, ConnectionManagerConfig},
+    cluster::ClusterClient,
+    cluster_async::ClusterConnection,
+    cmd, pipe,
+    sentinel::{SentinelClient, SentinelNodeConnectionInfo, SentinelServerType},
+    Client, Cmd, Pipeline, RedisConnectionInfo, RedisError, RedisFuture, Script, Value,
+};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
 
 const SCRIPT_WITH_DEDUPLICATION: &str = r#"
+    local offset = 2
     for index = 1, tonumber(ARGV[1]) do
+        local count = tonumber(ARGV[offset + 1])
         if redis.call("GET", KEYS[index]) == false then
-            local offset = index * 6 - 5
-            redis.call("SETEX", KEYS[index], ARGV[offset + 6], 1)
-            redis.call("PUBLISH", ARGV[offset + 1] .. '.' .. ARGV[offset + 2], ARGV[offset + 5])
-            redis.call("PUBLISH", ARGV[offset + 1] .. '.' .. ARGV[offset + 2] .. '::' .. ARGV[offset + 4], ARGV[offset + 5])
-            for namespace in ARGV[offset + 3]:gmatch('[^,]+') do
-                redis.call("PUBLISH", ARGV[offset + 1] .. '.' .. namespace .. '::' .. ARGV[offset + 2], ARGV[offset + 5])
+            redis.call("SETEX", KEYS[index], ARGV[2], 1)
+            for channel = offset + 2, offset + count + 1 do
+                redis.call("PUBLISH", ARGV[channel], ARGV[offset + count + 2])
             end
         end
+        offset = offset + count + 2
     end
 "#;
 
 const SCRIPT_WITHOUT_DEDUPLICATION: &str = r#"
+    local offset = 1
     for index = 1, tonumber(ARGV[1]) do
-        local offset = index * 5 - 4
-        redis.call("PUBLISH", ARGV[offset + 1] .. '.' .. ARGV[offset + 2], ARGV[offset + 5])
-        redis.call("PUBLISH", ARGV[offset + 1] .. '.' .. ARGV[offset + 2] .. '::' .. ARGV[offset + 4], ARGV[offset + 5])
-        for namespace in ARGV[offset + 3]:gmatch('[^,]+') do
-            redis.call("PUBLISH", ARGV[offset + 1] .. '.' .. namespace .. '::' .. ARGV[offset + 2], ARGV[offset + 5])
+        local count = tonumber(ARGV[offset + 1])
+        for channel = offset + 2, offset + count + 1 do
+            redis.call("PUBLISH", ARGV[channel], ARGV[offset + count + 2])
         end
+        offset = offset + count + 2
     end
 "#;
 
+const SCRIPT_SHARDED: &str = r#"
+    for index = 1, #KEYS do
+        redis.call("SPUBLISH", KEYS[index], ARGV[index])
+    end
+"#;
+
+const SCRIPT_STREAM_WITH_DEDUPLICATION: &str = r#"
+    local count = tonumber(ARGV[1])
+    for index = 1, count do
+        if redis.call("GET", KEYS[index]) == false then
+            local offset = index * 5 - 2
+            redis.call("SETEX", KEYS[index], ARGV[3], 1)
+            if ARGV[2] == '' then
+                redis.call("XADD", KEYS[count + index], "*", "db", ARGV[offset + 1], "collection", ARGV[offset + 2], "namespaces", ARGV[offset + 3], "id", ARGV[offset + 4], "payload", ARGV[offset + 5])
+            else
+                redis.call("XADD", KEYS[count + index], "MAXLEN", "~", ARGV[2], "*", "db", ARGV[offset + 1], "collection", ARGV[offset + 2], "namespaces", ARGV[offset + 3], "id", ARGV[offset + 4], "payload", ARGV[offset + 5])
+            end
+        end
+    end
+"#;
+
+const SCRIPT_STREAM_WITHOUT_DEDUPLICATION: &str = r#"
+    for index = 1, tonumber(ARGV[1]) do
+        local offset = index * 5 - 3
+        if ARGV[2] == '' then
+            redis.call("XADD", KEYS[index], "*", "db", ARGV[offset + 1], "collection", ARGV[offset + 2], "namespaces", ARGV[offset + 3], "id", ARGV[offset + 4], "payload", ARGV[offset + 5])
+        else
+            redis.call("XADD", KEYS[index], "MAXLEN", "~", ARGV[2], "*", "db", ARGV[offset + 1], "collection", ARGV[offset + 2], "namespaces", ARGV[offset + 3], "id", ARGV[offset + 4], "payload", ARGV[offset + 5])
+        end
+    end
+"#;
+
+/// Redis Sentinel deployment, i.e., nodes and the monitored master.
+pub struct SentinelConfig {
+    /// Credentials and database of the master.
+    pub master: RedisConnectionInfo,
+    pub nodes: Vec<String>,
+    pub service_name: String,
+}
+
+/// Either a single node, a Redis Cluster, or a Redis Sentinel connection. All
+/// of them reconnect automatically.
+#[derive(Clone)]
+enum Connection {
+    Cluster(ClusterConnection),
+    Sentinel(Arc<SentinelConnection>),
+    Single(ConnectionManager),
+}
+
+impl Connection {
+    /// Recovers the connection after an error. Only the Redis Sentinel one has
+    /// to do anything, i.e., rediscover the master (it may be a failover).
+    async fn recover(&self) -> Result<(), RedisError> {
+        match self {
+            Self::Sentinel(connection) => connection.reconnect().await,
+            Self::Cluster(_) | Self::Single(_) => Ok(()),
+        }
+    }
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Self::Cluster(connection) => connection.req_packed_command(cmd),
+            Self::Sentinel(connection) => {
+                let mut connection = connection.connection_manager();
+                Box::pin(async move { connection.req_packed_command(cmd).await })
+            }
+            Self::Single(connection) => connection.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Self::Cluster(connection) => connection.req_packed_commands(pipeline, offset, count),
+            Self::Sentinel(connection) => {
+                let mut connection = connection.connection_manager();
+                Box::pin(async move {
+                    connection
+                        .req_packed_commands(pipeline, offset, count)
+                        .await
+                })
+            }
+            Self::Single(connection) => connection.req_packed_commands(pipeline, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Self::Cluster(connection) => connection.get_db(),
+            Self::Sentinel(connection) => connection.connection_manager().get_db(),
+            Self::Single(connection) => connection.get_db(),
+        }
+    }
+}
+
+/// Connection to the current Redis Sentinel master, replaced on failovers.
+struct SentinelConnection {
+    client: tokio::sync::Mutex<SentinelClient>,
+    config: ConnectionManagerConfig,
+    connection_manager: Mutex<ConnectionManager>,
+}
+
+impl SentinelConnection {
+    async fn new(sentinel: &SentinelConfig, config: &Config) -> Result<Self, RedisError> {
+        let mut client = SentinelClient::build(
+            sentinel.nodes.clone(),
+            sentinel.service_name.clone(),
+            Some(
+                SentinelNodeConnectionInfo::default()
+                    .set_redis_connection_info(sentinel.master.clone()),
+            ),
+            SentinelServerType::Master,
+        )?;
+
+        let config = config.redis_connection_manager_config.clone();
+        let connection_manager = client
+            .async_get_client()
+            .await?
+            .get_connection_manager_with_config(config.clone())
+            .await?;
+
+        Ok(Self {
+            client: tokio::sync::Mutex::new(client),
+            config,
+            connection_manager: Mutex::new(connection_manager),
+        })
+    }
+
+    fn connection_manager(&self) -> ConnectionManager {
+        self.connection_manager.lock().unwrap().clone()
+    }
+
+    async fn reconnect(&self) -> Result<(), RedisError> {
+        let client = self.client.lock().await.async_get_client().await?;
+        let connection_manager = client
+            .get_connection_manager_with_config(self.config.clone())
+            .await?;
+
+        *self.connection_manager.lock().unwrap() = connection_manager;
+        eprintln!("Redis Sentinel master rediscovered.");
+        Ok(())
+    }
+}
+
 pub struct Redis {
-    connection_manager: ConnectionManager,
+    connection: Connection,
     script: Script,
 }
 
 impl Redis {
     pub async fn new(config: &Config) -> Result<Self, RedisError> {
-        let connection_manager = Client::open(config.redis_url.as_str())?
-            .get_connection_manager_with_config(config.redis_connection_manager_config.clone())
-            .await?;
+        let connection = if let Some(sentinel) = &config.redis_sentinel {
+            Connection::Sentinel(Arc::new(SentinelConnection::new(sentinel, config).await?))
+        } else if config.redis_cluster {
+            Connection::Cluster(
+                ClusterClient::new(config.redis_url.split(','))?
+                    .get_async_connection_with_config(config.redis_cluster_config.clone())
+                    .await?,
+            )
+        } else {
+            Connection::Single(
+                Client::open(config.redis_url.as_str())?
+                    .get_connection_manager_with_config(
+                        config.redis_connection_manager_config.clone(),
+                    )
+                    .await?,
+            )
+        };
 
         println!("Redis connection initialized.");
-        let script = Script::new(match config.deduplication {
-            None => SCRIPT_WITHOUT_DEDUPLICATION,
-            Some(_) => SCRIPT_WITH_DEDUPLICATION,
+        let script = Script::new(match (&config.redis_stream, config.deduplication) {
+            (None, _) if config.redis_sharded_pubsub => SCRIPT_SHARDED,
+            (None, None) => SCRIPT_WITHOUT_DEDUPLICATION,
+            (None, Some(_)) => SCRIPT_WITH_DEDUPLICATION,
+            (Some(_), None) => SCRIPT_STREAM_WITHOUT_DEDUPLICATION,
+            (Some(_), Some(_)) => SCRIPT_STREAM_WITH_DEDUPLICATION,
         });
 
-        Ok(Self {
-            connection_manager,
-            script,
-        })
+        Ok(Self { connection, script })
     }
 
-    pub async fn publish(&mut self, config: &Config, events: Vec<Event>) -> Result<(), RedisError> {
+    pub async fn ping(&mut self) -> Result<(), RedisError> {
+        cmd("PING").query_async(&mut self.connection).await
+    }
+
+    pub async fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, RedisError> {
+        cmd("GET").arg(key).query_async(&mut self.connection).await
+    }
+
+    pub async fn set(&mut self, key: &str, value: Vec<u8>) -> Result<(), RedisError> {
+        cmd("SET")
+            .arg(key)
+            .arg(value)
+            .query_async(&mut self.connection)
+            .await
+    }
+
+    pub async fn publish(&self, config: &Config, mut events: Vec<Event>) -> Result<(), RedisError> {
+        for event in &mut events {
+            event.serialize_payload(config);
+        }
+
         if config.debug {
             for event in &events {
-                event.debug();
+                event.debug(config);
             }
         }
 
+        if config.redis_sharded_pubsub || matches!(self.connection, Connection::Cluster(_)) {
+            let (events, keys) = self.deduplicate(config, events).await?;
+            let result = if config.redis_sharded_pubsub {
+                self.publish_sharded(config, events).await
+            } else {
+                self.publish_cluster(config, events).await
+            };
+
+            if result.is_err() {
+                self.release(keys).await;
+            }
+
+            return result;
+        }
+
         let mut invocation = self.script.prepare_invoke();
         invocation.arg(events.len());
+        match &config.redis_stream {
+            None => {
+                // Channels are rendered here, so the script only publishes.
+                if let Some(deduplication) = config.deduplication {
+                    invocation.arg(deduplication);
+                }
 
-        for event in events {
-            invocation.arg(event.db);
-            invocation.arg(event.collection);
-            invocation.arg(event.namespaces);
-            invocation.arg(event.document_id);
-            invocation.arg(event.operation.into_ejson().to_string());
-
-            if let Some(deduplication) = config.deduplication {
-                invocation.arg(deduplication);
-                invocation.key(event.event_id.to_string());
+                for event in events {
+                    let channels = event.channels(config);
+                    invocation.arg(channels.len());
+                    invocation.arg(channels);
+                    invocation.arg(&event.payload);
+
+                    if config.deduplication.is_some() {
+                        invocation.key(event.deduplication_key());
+                    }
+                }
             }
+            Some(stream) => {
+                invocation.arg(
+                    config
+                        .redis_stream_max_length
+                        .map_or_else(String::new, |max_length| max_length.to_string()),
+                );
+
+                // Deduplication keys go first, followed by the stream keys.
+                if let Some(deduplication) = config.deduplication {
+                    invocation.arg(deduplication);
+                    for event in &events {
+                        invocation.key(event.deduplication_key());
+                    }
+                }
+
+                for event in events {
+                    invocation.key(stream_key(config, stream, &event));
+                    invocation.arg(event.db);
+                    invocation.arg(event.collection);
+                    invocation.arg(event.namespaces);
+                    invocation.arg(event.document_id);
+                    invocation.arg(event.payload);
+                }
+            }
+        }
+
+        let invocation = &invocation;
+        with_retries(config, &self.connection, |mut connection| async move {
+            invocation.invoke_async(&mut connection).await
+        })
+        .await
+    }
+
+    /// Publishes one marker message for each of the completed transactions.
+    pub async fn publish_transactions(
+        &self,
+        config: &Config,
+        transactions: Vec<Bson>,
+    ) -> Result<(), RedisError> {
+        let Some(channel) = &config.transaction_marker_channel else {
+            return Ok(());
+        };
+
+        if transactions.is_empty() {
+            return Ok(());
         }
 
-        let retry_limit = config.redis_publish_retry_count;
-        for retry in 0..=retry_limit {
-            match invocation.invoke_async(&mut self.connection_manager).await {
-                Ok(()) => {
-                    if retry > 0 {
-                        eprintln!("Redis publication succeeded (retry #{retry})");
+        let command = if config.redis_sharded_pubsub {
+            "SPUBLISH"
+        } else {
+            "PUBLISH"
+        };
+
+        let mut pipeline = pipe();
+        for transaction in transactions {
+            let payload = transaction.into_ejson().to_string();
+            pipeline.cmd(command).arg(channel).arg(payload).ignore();
+        }
+
+        let pipeline = &pipeline;
+        with_retries(config, &self.connection, |mut connection| async move {
+            pipeline.query_async(&mut connection).await
+        })
+        .await
+    }
+
+    /// Lua scripts cannot access keys from different hash slots on Redis
+    /// Cluster, so all commands are sent directly instead.
+    async fn publish_cluster(&self, config: &Config, events: Vec<Event>) -> Result<(), RedisError> {
+        // Publications have no keys, so they are all sent to the same node (in
+        // order). Streams are grouped by their keys.
+        let mut pipelines = BTreeMap::<Option<String>, Pipeline>::new();
+        for event in events {
+            let stream = config
+                .redis_stream
+                .as_deref()
+                .map(|stream| stream_key(config, stream, &event));
+            let pipeline = pipelines.entry(stream.clone()).or_insert_with(pipe);
+            match stream {
+                None => {
+                    for channel in event.channels(config) {
+                        pipeline
+                            .cmd("PUBLISH")
+                            .arg(channel)
+                            .arg(&event.payload)
+                            .ignore();
+                    }
+                }
+                Some(stream) => {
+                    pipeline.cmd("XADD").arg(stream);
+                    if let Some(max_length) = config.redis_stream_max_length {
+                        pipeline.arg("MAXLEN").arg("~").arg(max_length);
                     }
 
-                    return Ok(());
+                    pipeline
+                        .arg("*")
+                        .arg("db")
+                        .arg(event.db)
+                        .arg("collection")
+                        .arg(event.collection)
+                        .arg("namespaces")
+                        .arg(event.namespaces)
+                        .arg("id")
+                        .arg(event.document_id)
+                        .arg("payload")
+                        .arg(event.payload)
+                        .ignore();
+                }
+            }
+        }
+
+        for pipeline in pipelines.values() {
+            with_retries(config, &self.connection, |mut connection| async move {
+                pipeline.query_async(&mut connection).await
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sharded publications are grouped by their hash slots, as a single Lua
+    /// script can only access one of them.
+    async fn publish_sharded(&self, config: &Config, events: Vec<Event>) -> Result<(), RedisError> {
+        let mut invocations = BTreeMap::new();
+        for event in events {
+            for channel in event.channels(config) {
+                invocations
+                    .entry(hash_slot(&channel))
+                    .or_insert_with(|| self.script.prepare_invoke())
+                    .key(channel)
+                    .arg(&event.payload);
+            }
+        }
+
+        for invocation in invocations.values() {
+            with_retries(config, &self.connection, |mut connection| async move {
+                invocation.invoke_async(&mut connection).await
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the deduplication keys one by one (each of them can be stored on a
+    /// different node) and returns only the events that were not seen before,
+    /// together with their keys (see `release`).
+    async fn deduplicate(
+        &self,
+        config: &Config,
+        events: Vec<Event>,
+    ) -> Result<(Vec<Event>, Vec<String>), RedisError> {
+        let Some(deduplication) = config.deduplication else {
+            return Ok((events, vec![]));
+        };
+
+        let keys: Vec<_> = events.iter().map(Event::deduplication_key).collect();
+        let results = try_join_all(keys.iter().map(|key| {
+            let mut connection = self.connection.clone();
+            async move {
+                cmd("SET")
+                    .arg(key)
+                    .arg(1)
+                    .arg("EX")
+                    .arg(deduplication)
+                    .arg("NX")
+                    .query_async::<Option<String>>(&mut connection)
+                    .await
+            }
+        }))
+        .await?;
+
+        Ok(events
+            .into_iter()
+            .zip(keys)
+            .zip(results)
+            .filter_map(|(event, result)| result.map(|_| event))
+            .unzip())
+    }
+
+    /// Deletes the deduplication keys of events that failed to publish, so
+    /// they can be published again (e.g., after a restart or by another
+    /// instance). If that fails too, the events are lost until the keys expire.
+    async fn release(&self, keys: Vec<String>) {
+        let results = join_all(keys.into_iter().map(|key| {
+            let mut connection = self.connection.clone();
+            async move { cmd("DEL").arg(key).query_async::<()>(&mut connection).await }
+        }))
+        .await;
+
+        for error in results.into_iter().filter_map(Result::err) {
+            eprintln!("Redis deduplication key release error: {error:?}");
+        }
+    }
+}
+
+fn stream_key(config: &Config, stream: &str, event: &Event) -> String {
+    if config.redis_stream_per_collection {
+        format!("{stream}:{}.{}", event.db, event.collection)
+    } else {
+        stream.to_string()
+    }
+}
+
+/// Runs the `action` until it succeeds, retrying I/O errors up to
+/// `redis_publish_retry_count` times. The connection is recovered before every
+/// retry, so the limit is kept even if the Redis Sentinel master changes.
+async fn with_retries<F: Future<Output = Result<(), RedisError>>>(
+    config: &Config,
+    connection: &Connection,
+    mut action: impl FnMut(Connection) -> F,
+) -> Result<(), RedisError> {
+    let retry_limit = config.redis_publish_retry_count;
+    for retry in 0..=retry_limit {
+        match action(connection.clone()).await {
+            Ok(()) => {
+                if retry > 0 {
+                    eprintln!("Redis publication succeeded (retry #{retry})");
                 }
-                // All I/O errors can be safely retried.
-                Err(error) if !error.is_io_error() || retry == retry_limit => return Err(error),
-                Err(error) => {
-                    eprintln!("Redis error (retry #{retry}): {error:?}");
+
+                return Ok(());
+            }
+            // All I/O errors can be safely retried. So can the writes rejected
+            // by a former master, demoted to a replica during a failover.
+            Err(error)
+                if !(error.is_io_error() || error.code() == Some("READONLY"))
+                    || retry == retry_limit =>
+            {
+                return Err(error)
+            }
+            Err(error) => {
+                eprintln!("Redis error (retry #{retry}): {error:?}");
+                if let Err(error) = connection.recover().await {
+                    eprintln!("Redis recovery error (retry #{retry}): {error:?}");
                 }
             }
         }
+    }
+
+    unreachable!()
+}
+
+/// Redis Cluster hash slot of the `key` (CRC16 of its hash tag, if any).
+fn hash_slot(key: &str) -> u16 {
+    let mut key = key.as_bytes();
+    if let Some(start) = key.iter().position(|byte| *byte == b'{') {
+        if let Some(length) = key[start + 1..].iter().position(|byte| *byte == b'}') {
+            if length > 0 {
+                key = &key[start + 1..start + 1 + length];
+            }
+        }
+    }
+
+    let mut crc = 0_u16;
+    for byte in key {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 == 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x1021
+            };
+        }
+    }
+
+    crc % 16384
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_slot_of_key() {
+        assert_eq!(hash_slot("foo"), 12182);
+        assert_eq!(hash_slot("123456789"), 12739);
+    }
 
-        unreachable!()
+    #[test]
+    fn hash_slot_of_hash_tag() {
+        assert_eq!(hash_slot("{user1000}.following"), hash_slot("user1000"));
+        assert_eq!(
+            hash_slot("{user1000}.following"),
+            hash_slot("{user1000}.followers")
+        );
+        // Only the first `{` counts, and empty tags are ignored.
+        assert_eq!(hash_slot("foo{{bar}}zap"), hash_slot("{bar"));
+        assert_eq!(hash_slot("foo{}{bar}"), 8363);
     }
 }
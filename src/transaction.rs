@@ -0,0 +1,77 @@
+use crate::{config::Config, event::Event};
+use bson::{Bson, Timestamp};
+use std::mem::take;
+
+/// Cluster times reached by both change streams, either by their events or by
+/// their resume tokens (e.g., of empty batches). All events up to them were
+/// already received.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub primary: Option<Timestamp>,
+    pub secondary: Option<Timestamp>,
+}
+
+/// Transactions that may still have events to come. All events of one
+/// transaction have the same cluster time, so it is complete once all of the
+/// change streams moved past it. The events of both streams can interleave, so
+/// none of them is enough on its own.
+#[derive(Default)]
+pub struct Transactions {
+    /// Identifiers and cluster times of the incomplete transactions.
+    open: Vec<(Bson, Timestamp)>,
+    progress: Progress,
+}
+
+impl Transactions {
+    /// Tracks the progress of the event's stream and its transaction (if any).
+    pub fn track(&mut self, event: &Event) {
+        let position = if event.primary {
+            &mut self.progress.primary
+        } else {
+            &mut self.progress.secondary
+        };
+        *position = (*position).max(Some(event.timestamp));
+
+        if let Some(transaction) = &event.transaction {
+            if !self.open.iter().any(|(open, _)| open == transaction) {
+                self.open.push((transaction.clone(), event.timestamp));
+            }
+        }
+    }
+
+    /// Tracks the progress of both streams reported by `Mongo`.
+    pub fn advance(&mut self, progress: Progress) {
+        self.progress.primary = self.progress.primary.max(progress.primary);
+        self.progress.secondary = self.progress.secondary.max(progress.secondary);
+    }
+
+    /// Whether any of the tracked transactions is complete.
+    pub fn has_complete(&self, config: &Config) -> bool {
+        self.open
+            .iter()
+            .any(|(_, timestamp)| self.is_complete(config, *timestamp))
+    }
+
+    pub const fn has_open(&self) -> bool {
+        !self.open.is_empty()
+    }
+
+    /// Whether all of the change streams moved past the cluster time.
+    pub fn is_complete(&self, config: &Config, timestamp: Timestamp) -> bool {
+        self.progress.primary.is_some_and(|x| x > timestamp)
+            && (config.full_document_collections.is_none()
+                || self.progress.secondary.is_some_and(|x| x > timestamp))
+    }
+
+    /// Removes and returns the complete transactions (in order).
+    pub fn take_complete(&mut self, config: &Config) -> Vec<Bson> {
+        let (complete, open): (Vec<_>, _) = take(&mut self.open)
+            .into_iter()
+            .partition(|(_, timestamp)| self.is_complete(config, *timestamp));
+        self.open = open;
+        complete
+            .into_iter()
+            .map(|(transaction, _)| transaction)
+            .collect()
+    }
+}